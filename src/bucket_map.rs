@@ -0,0 +1,184 @@
+//! Purpose-built open-addressing map keyed on station name bytes.
+//!
+//! The 1brc dataset has at most ~10 000 distinct stations, so a table
+//! pre-sized to `INITIAL_CAPACITY_POW2` almost never probes more than a
+//! couple of slots, which beats a general-purpose SwissTable's overhead
+//! for this workload.
+
+const INITIAL_CAPACITY_POW2: usize = 1 << 15;
+const MAX_SEARCH: usize = 32;
+
+#[derive(Debug)]
+pub struct Station {
+    pub min: f64,
+    pub max: f64,
+    pub sum: i128,
+    pub count: u128,
+}
+impl Station {
+    #[inline(always)]
+    pub fn from(value: f64) -> Station {
+        Station {
+            min: value,
+            max: value,
+            sum: (value * 10.0) as i128,
+            count: 1,
+        }
+    }
+    #[inline(always)]
+    pub fn update(&mut self, value: f64) {
+        if value < self.min {
+            self.min = value;
+        }
+        if value > self.max {
+            self.max = value;
+        }
+        self.sum += (value * 10.0) as i128;
+        self.count += 1;
+    }
+    /// Computes the final `min`/`mean`/`max`/`count` without consuming the
+    /// station, so every output format can render from the same summary.
+    #[inline(always)]
+    pub fn summarize(&self) -> Summary {
+        let correct_sum: f64 = (self.sum as f64) / 10.0;
+        Summary {
+            min: self.min,
+            mean: correct_sum / (self.count as f64),
+            max: self.max,
+            count: self.count,
+        }
+    }
+    #[inline(always)]
+    pub fn join(&mut self, tmp_station: &Station) {
+        if tmp_station.min < self.min {
+            self.min = tmp_station.min;
+        }
+        if tmp_station.max > self.max {
+            self.max = tmp_station.max;
+        }
+        self.sum += tmp_station.sum;
+        self.count += tmp_station.count;
+    }
+}
+
+/// A station's final aggregates, ready to be rendered in any output format.
+#[derive(Debug)]
+pub struct Summary {
+    pub min: f64,
+    pub mean: f64,
+    pub max: f64,
+    pub count: u128,
+}
+
+struct Slot {
+    name: Box<[u8]>,
+    station: Station,
+}
+
+/// Flat, power-of-two-sized open-addressing table mapping station name
+/// bytes to a [`Station`]. Collisions are resolved with linear probing
+/// bounded by [`MAX_SEARCH`]; the table doubles and rehashes whenever a
+/// probe runs past that bound without finding a match or an empty slot.
+pub struct BucketMap {
+    slots: Vec<Option<Slot>>,
+    capacity_pow2: usize,
+}
+impl Default for BucketMap {
+    fn default() -> BucketMap {
+        BucketMap::new()
+    }
+}
+impl BucketMap {
+    /// Creates a table pre-sized to [`INITIAL_CAPACITY_POW2`], which the
+    /// 1brc dataset's ≤10 000 distinct stations almost never overflow.
+    pub fn new() -> BucketMap {
+        Self::with_capacity(INITIAL_CAPACITY_POW2)
+    }
+
+    pub fn with_capacity(capacity_hint: usize) -> BucketMap {
+        let capacity_pow2: usize = capacity_hint.max(1).next_power_of_two();
+        BucketMap {
+            slots: (0..capacity_pow2).map(|_| None).collect(),
+            capacity_pow2,
+        }
+    }
+
+    #[inline(always)]
+    fn hash(name: &[u8]) -> u64 {
+        // FNV-1a
+        const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+        const FNV_PRIME: u64 = 0x100000001b3;
+        let mut hash: u64 = FNV_OFFSET_BASIS;
+        for &byte in name {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(FNV_PRIME);
+        }
+        hash
+    }
+
+    /// Finds the slot holding `name`, or the first empty slot within
+    /// `MAX_SEARCH` probes, growing and rehashing the table as needed.
+    fn probe(&mut self, name: &[u8]) -> usize {
+        loop {
+            let mask: usize = self.capacity_pow2 - 1;
+            let start: usize = (Self::hash(name) as usize) & mask;
+            for offset in 0..MAX_SEARCH {
+                let index: usize = (start + offset) & mask;
+                match &self.slots[index] {
+                    Some(slot) if slot.name.as_ref() == name => return index,
+                    None => return index,
+                    Some(_) => continue,
+                }
+            }
+            self.grow();
+        }
+    }
+
+    fn grow(&mut self) {
+        let new_capacity_pow2: usize = self.capacity_pow2 * 2;
+        let old_slots: Vec<Option<Slot>> =
+            std::mem::replace(&mut self.slots, (0..new_capacity_pow2).map(|_| None).collect());
+        self.capacity_pow2 = new_capacity_pow2;
+        for slot in old_slots.into_iter().flatten() {
+            let index: usize = self.probe(&slot.name);
+            self.slots[index] = Some(slot);
+        }
+    }
+
+    #[inline(always)]
+    pub fn insert_or_update(&mut self, name: &[u8], value: f64) {
+        let index: usize = self.probe(name);
+        match &mut self.slots[index] {
+            Some(slot) => slot.station.update(value),
+            None => {
+                self.slots[index] = Some(Slot {
+                    name: Box::from(name),
+                    station: Station::from(value),
+                });
+            }
+        }
+    }
+
+    /// Combines `station` into the entry for `name`, inserting it as a
+    /// new entry if `name` hasn't been seen yet. Used to merge per-thread
+    /// tables into a single result via probe-and-combine.
+    pub fn merge_station(&mut self, name: &[u8], station: Station) {
+        let index: usize = self.probe(name);
+        match &mut self.slots[index] {
+            Some(slot) => slot.station.join(&station),
+            None => {
+                self.slots[index] = Some(Slot {
+                    name: Box::from(name),
+                    station,
+                });
+            }
+        }
+    }
+
+    /// Drains occupied slots as owned `(name, station)` pairs.
+    pub fn drain(&mut self) -> impl Iterator<Item = (Box<[u8]>, Station)> + '_ {
+        self.slots.iter_mut().filter_map(|slot| {
+            slot.take().map(|slot| (slot.name, slot.station))
+        })
+    }
+}
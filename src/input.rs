@@ -0,0 +1,70 @@
+//! Transparent decoding layer for the input path.
+//!
+//! `read_chunks` only needs a byte source behind `Box<dyn Read + Send>`; it
+//! doesn't care whether those bytes come straight off disk, out of a
+//! decompressor, or out of a stream cipher. This module picks the right
+//! stack of adapters for a given path and an optional cipher key, so the
+//! chunk-splitting logic never has to know the file is compressed or
+//! encrypted.
+
+use std::io::{self, Read};
+
+use chacha20::cipher::{KeyIvInit, StreamCipher};
+use chacha20::ChaCha20;
+use flate2::read::GzDecoder;
+
+/// Opens `path`, layering a decompressor on top by extension (`.gz`, `.zst`)
+/// and, if `cipher_key` is given, a ChaCha20 stream-cipher decoder on top of
+/// that (the file is assumed to have been encrypted before compression, so
+/// undoing it means decompressing first and decrypting what comes out).
+pub fn open_source(path: &str, cipher_key: Option<[u8; 32]>) -> io::Result<Box<dyn Read + Send>> {
+    let file = std::fs::File::open(path)?;
+    let mut reader: Box<dyn Read + Send> = Box::new(file);
+
+    reader = match path {
+        p if p.ends_with(".gz") => Box::new(GzDecoder::new(reader)),
+        p if p.ends_with(".zst") => Box::new(zstd::Decoder::new(reader)?),
+        _ => reader,
+    };
+
+    if let Some(key) = cipher_key {
+        reader = Box::new(ChaChaReader::new(reader, key));
+    }
+
+    Ok(reader)
+}
+
+/// Derives a 32-byte ChaCha20 key by repeating `passphrase`'s bytes. Not a
+/// cryptographically sound KDF, just enough to turn a CLI passphrase into
+/// the fixed-size key the cipher needs.
+pub fn derive_key(passphrase: &str) -> [u8; 32] {
+    assert!(!passphrase.is_empty(), "--cipher-key must not be empty");
+    let mut key = [0u8; 32];
+    for (byte, slot) in passphrase.bytes().cycle().zip(key.iter_mut()) {
+        *slot = byte;
+    }
+    key
+}
+
+/// Decrypts a ChaCha20 stream as it's read, so the chunk reader downstream
+/// sees plaintext bytes without knowing encryption is involved.
+struct ChaChaReader {
+    inner: Box<dyn Read + Send>,
+    cipher: ChaCha20,
+}
+impl ChaChaReader {
+    fn new(inner: Box<dyn Read + Send>, key: [u8; 32]) -> ChaChaReader {
+        let nonce = [0u8; 12];
+        ChaChaReader {
+            inner,
+            cipher: ChaCha20::new(&key.into(), &nonce.into()),
+        }
+    }
+}
+impl Read for ChaChaReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read_len: usize = self.inner.read(buf)?;
+        self.cipher.apply_keystream(&mut buf[..read_len]);
+        Ok(read_len)
+    }
+}
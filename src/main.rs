@@ -1,192 +1,268 @@
 #[cfg(test)]
 mod tests;
 
-use hashbrown::HashMap;
+mod bucket_map;
+mod input;
+
+use bucket_map::{BucketMap, Summary};
+use crossbeam_channel::{bounded, unbounded, Receiver, Sender};
 use std::{
-    fs,
+    env,
     io::{self, Read, Write},
     str,
-    sync::{
-        mpsc::{self, Sender},
-        Arc, Mutex,
-    },
-    thread, vec,
+    sync::mpsc,
+    thread,
 };
 
 const BUFFER_SIZE: usize = 100_000_000;
 const PATH: &str = "1brc/data/measurements.txt";
 const N_MAX_THREADS: usize = 8;
+// how many chunks the reader is allowed to stay ahead of the workers
+const READ_AHEAD: usize = 2;
 
-#[derive(Debug)]
-struct Station {
-    min: f64,
-    max: f64,
-    sum: i128,
-    count: u128,
+/// Output format selected through `--format`. `Brc` is the original
+/// brace-delimited line; `Json` and `Csv` exist so the result can be
+/// consumed by downstream tooling without re-parsing the brace syntax.
+#[derive(Clone, Copy)]
+enum OutputFormat {
+    Brc,
+    Json,
+    Csv,
 }
-impl Station {
-    #[inline(always)]
-    fn from(value: f64) -> Station {
-        Station {
-            min: value,
-            max: value,
-            sum: (value * 10.0) as i128,
-            count: 1,
+impl OutputFormat {
+    fn parse(value: &str) -> OutputFormat {
+        match value {
+            "brc" => OutputFormat::Brc,
+            "json" => OutputFormat::Json,
+            "csv" => OutputFormat::Csv,
+            other => panic!("unsupported --format `{}`, expected brc, json, or csv", other),
         }
     }
-    #[inline(always)]
-    fn update(&mut self, value: f64) {
-        if value < self.min {
-            self.min = value;
-        }
-        if value > self.max {
-            self.max = value;
+}
+/// Parsed command-line options: the input path/format plus the optional
+/// cipher key for encrypted measurement files.
+struct CliArgs {
+    path: String,
+    format: OutputFormat,
+    cipher_key: Option<[u8; 32]>,
+}
+fn parse_args(args: impl Iterator<Item = String>) -> CliArgs {
+    let mut path = String::from(PATH);
+    let mut format = OutputFormat::Brc;
+    let mut cipher_key: Option<[u8; 32]> = None;
+
+    let mut args = args.skip(1);
+    while let Some(arg) = args.next() {
+        if let Some(value) = arg.strip_prefix("--format=") {
+            format = OutputFormat::parse(value);
+        } else if arg == "--format" {
+            format = OutputFormat::parse(&args.next().expect("--format expects a value"));
+        } else if let Some(value) = arg.strip_prefix("--cipher-key=") {
+            cipher_key = Some(input::derive_key(value));
+        } else if arg == "--cipher-key" {
+            let value = args.next().expect("--cipher-key expects a value");
+            cipher_key = Some(input::derive_key(&value));
+        } else {
+            path = arg;
         }
-        self.sum += (value * 10.0) as i128;
-        self.count += 1;
-    }
-    #[inline(always)]
-    fn drain(self) -> String {
-        let correct_sum: f64 = (self.sum as f64) / 10.0;
-        let mean: f64 = correct_sum / (self.count as f64);
-        format!("={}/{:.1}/{}", self.min, mean, self.max)
     }
-    #[inline(always)]
-    fn join(&mut self, tmp_station: &Station) {
-        if tmp_station.min < self.min {
-            self.min = tmp_station.min;
-        }
-        if tmp_station.max > self.max {
-            self.max = tmp_station.max;
-        }
-        self.sum += tmp_station.sum;
-        self.count += tmp_station.count;
+    CliArgs {
+        path,
+        format,
+        cipher_key,
     }
 }
 
-struct BufferManager {
-    file: fs::File,
-    buffer: Option<Vec<u8>>,
-    buffer_offset: usize,
-}
-impl BufferManager {
-    fn with(file: fs::File) -> BufferManager {
-        BufferManager {
-            file,
-            buffer: Some(vec![0; BUFFER_SIZE]),
-            buffer_offset: 0,
-        }
-    }
-    #[inline(always)]
-    fn request_buffer(&mut self, mut new_buffer: Vec<u8>) -> Option<Vec<u8>> {
-        let mut old_buffer: Vec<u8> = self.buffer.take()?;
-        let copied_data_len: usize = self
-            .file
-            .read(&mut old_buffer[self.buffer_offset..])
-            .unwrap();
-
-        if self.buffer_offset + copied_data_len < BUFFER_SIZE {
-            old_buffer.truncate(self.buffer_offset + copied_data_len);
-            return match old_buffer.is_empty() {
-                true => None,
-                false => Some(old_buffer),
-            };
-        }
+fn main() -> io::Result<()> {
+    let args: CliArgs = parse_args(env::args());
+    let source: Box<dyn Read + Send> = input::open_source(&args.path, args.cipher_key)?;
 
-        for (offset, byte) in old_buffer.iter().rev().enumerate() {
-            if *byte == b'\n' {
-                self.buffer_offset = offset;
-                break;
-            }
-        }
-        // since the buffer is full there must be a linebreak
+    let (chunk_sender, chunk_receiver) = bounded::<Vec<u8>>(READ_AHEAD);
+    let (buffer_sender, buffer_receiver) = unbounded::<Vec<u8>>();
+    for _ in 0..(N_MAX_THREADS + READ_AHEAD) {
+        buffer_sender.send(vec![0; BUFFER_SIZE]).unwrap();
+    }
 
-        if self.buffer_offset > 0 {
-            let new_buffer_slice: &mut [u8] = &mut new_buffer[..self.buffer_offset];
-            new_buffer_slice
-                .copy_from_slice(&old_buffer[(old_buffer.len() - self.buffer_offset)..]);
-        }
-        old_buffer.truncate(old_buffer.len() - self.buffer_offset - 1);
+    let (map_sender, map_receiver) = mpsc::channel::<BucketMap>();
 
-        self.buffer = Some(new_buffer);
-        Some(old_buffer)
-    }
-}
-fn main() -> io::Result<()> {
-    let buffer_manager: Arc<Mutex<BufferManager>> =
-        Arc::new(Mutex::new(BufferManager::with(fs::File::open(PATH)?)));
-    let (map_sender, map_receiver) = mpsc::channel::<HashMap<String, Station>>();
+    thread::spawn(move || read_chunks(source, chunk_sender, buffer_receiver).unwrap());
 
     for _ in 0..N_MAX_THREADS {
-        new_thread(buffer_manager.clone(), map_sender.clone());
+        new_thread(
+            chunk_receiver.clone(),
+            buffer_sender.clone(),
+            map_sender.clone(),
+        );
     }
 
-    let mut map: HashMap<String, Station> = map_receiver.recv().unwrap();
+    let mut map: BucketMap = map_receiver.recv().unwrap();
     for _ in 1..N_MAX_THREADS {
         for (name, tmp_station) in map_receiver.recv().unwrap().drain() {
-            map.entry(name)
-                .and_modify(|station| station.join(&tmp_station))
-                .or_insert(tmp_station);
+            map.merge_station(&name, tmp_station);
         }
     }
-    print_map(map);
+    print_map(map, args.format);
     Ok(())
 }
 #[inline(always)]
-fn print_map(mut map: HashMap<String, Station>) {
-    let mut result: Vec<String> = Vec::new();
-    for (name, value) in map.drain() {
-        let values: String = value.drain();
-        result.push(name + &values);
+fn print_map(mut map: BucketMap, format: OutputFormat) {
+    let mut result: Vec<(String, Summary)> = Vec::new();
+    for (name, station) in map.drain() {
+        let name = String::from_utf8_lossy(&name).into_owned();
+        result.push((name, station.summarize()));
     }
-    result.sort();
+    result.sort_by(|(a, _), (b, _)| a.cmp(b));
 
     let mut lock = io::stdout().lock();
+    match format {
+        OutputFormat::Brc => print_brc(&result, &mut lock),
+        OutputFormat::Json => print_json(&result, &mut lock),
+        OutputFormat::Csv => print_csv(&result, &mut lock),
+    }
+}
+fn print_brc(result: &[(String, Summary)], writer: &mut impl Write) {
+    let mut iter = result.iter();
+    let (name, summary) = iter.next().unwrap();
+    write!(
+        writer,
+        "{{ {}={}/{:.1}/{}",
+        name, summary.min, summary.mean, summary.max
+    )
+    .unwrap();
+    for (name, summary) in iter {
+        write!(
+            writer,
+            ", {}={}/{:.1}/{}",
+            name, summary.min, summary.mean, summary.max
+        )
+        .unwrap();
+    }
+    writeln!(writer, " }}").unwrap();
+}
+fn print_json(result: &[(String, Summary)], writer: &mut impl Write) {
+    write!(writer, "{{").unwrap();
+    let mut first: bool = true;
+    for (name, summary) in result {
+        if !first {
+            write!(writer, ",").unwrap();
+        }
+        first = false;
+        write!(
+            writer,
+            "\"{}\":{{\"min\":{},\"mean\":{:.1},\"max\":{},\"count\":{}}}",
+            name, summary.min, summary.mean, summary.max, summary.count
+        )
+        .unwrap();
+    }
+    writeln!(writer, "}}").unwrap();
+}
+fn print_csv(result: &[(String, Summary)], writer: &mut impl Write) {
+    writeln!(writer, "station,min,mean,max,count").unwrap();
+    for (name, summary) in result {
+        writeln!(
+            writer,
+            "{},{},{:.1},{},{}",
+            name, summary.min, summary.mean, summary.max, summary.count
+        )
+        .unwrap();
+    }
+}
+
+/// Fills `buf` by calling `read()` repeatedly until it's full or the
+/// source is exhausted. A single `read()` call only fills the whole
+/// buffer for plain files; decompressing/decrypting readers routinely
+/// return far fewer bytes per call even with more data left to give.
+fn fill(source: &mut (impl Read + ?Sized), buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled: usize = 0;
+    while filled < buf.len() {
+        let read_len: usize = source.read(&mut buf[filled..])?;
+        if read_len == 0 {
+            break;
+        }
+        filled += read_len;
+    }
+    Ok(filled)
+}
+
+/// Owns the input file and is the only thread that reads from disk. Chunks
+/// are trimmed back to the last `b'\n'` before being handed to a worker;
+/// the trailing partial line is copied into the head of the next chunk.
+/// Chunk buffers are recycled through `buffer_receiver` instead of being
+/// reallocated every cycle.
+fn read_chunks(
+    mut source: Box<dyn Read + Send>,
+    chunk_sender: Sender<Vec<u8>>,
+    buffer_receiver: Receiver<Vec<u8>>,
+) -> io::Result<()> {
+    let mut buffer: Vec<u8> = buffer_receiver.recv().unwrap();
+    let mut buffer_offset: usize = 0;
+
+    loop {
+        let copied_len: usize = fill(&mut source, &mut buffer[buffer_offset..])?;
+        let data_len: usize = buffer_offset + copied_len;
+
+        if data_len < BUFFER_SIZE {
+            buffer.truncate(data_len);
+            if !buffer.is_empty() {
+                chunk_sender.send(buffer).unwrap();
+            }
+            return Ok(());
+        }
 
-    let mut iter: vec::IntoIter<String> = result.into_iter();
-    write!(lock, "{{ {}", iter.next().unwrap()).unwrap();
-    for element in iter {
-        write!(lock, ", {}", element).unwrap();
+        let mut split_at: usize = 0;
+        for (offset, byte) in buffer.iter().rev().enumerate() {
+            if *byte == b'\n' {
+                split_at = offset;
+                break;
+            }
+        }
+        // since the buffer is full there must be a linebreak
+
+        let mut next_buffer: Vec<u8> = buffer_receiver.recv().unwrap();
+        if split_at > 0 {
+            next_buffer[..split_at].copy_from_slice(&buffer[(buffer.len() - split_at)..]);
+        }
+        buffer.truncate(buffer.len() - split_at - 1);
+
+        chunk_sender.send(buffer).unwrap();
+        buffer = next_buffer;
+        buffer_offset = split_at;
     }
-    writeln!(lock, " }}").unwrap();
 }
 
 fn new_thread(
-    buffer_manager: Arc<Mutex<BufferManager>>,
-    map_sender: Sender<HashMap<String, Station>>,
+    chunk_receiver: Receiver<Vec<u8>>,
+    buffer_sender: Sender<Vec<u8>>,
+    map_sender: mpsc::Sender<BucketMap>,
 ) {
     thread::spawn(move || {
-        let mut map: HashMap<String, Station> = HashMap::with_capacity(10_000);
-        let mut possible_buffer: Option<Vec<u8>> = {
-            buffer_manager
-                .lock()
-                .unwrap()
-                .request_buffer(vec![0; BUFFER_SIZE])
-        };
-        while let Some(mut buffer) = possible_buffer {
+        let mut map: BucketMap = BucketMap::new();
+        while let Ok(mut buffer) = chunk_receiver.recv() {
             process_buffer(&buffer, &mut map);
             buffer.resize(BUFFER_SIZE, 0);
-            possible_buffer = buffer_manager.lock().unwrap().request_buffer(buffer);
+            // The reader thread (and its buffer_receiver) may already have
+            // exited by the time the last chunks are still being processed;
+            // there's nothing left to recycle the buffer into, which is fine.
+            let _ = buffer_sender.send(buffer);
         }
         map_sender.send(map).unwrap();
     });
 }
 #[inline(always)]
-fn process_buffer(buffer: &[u8], map: &mut HashMap<String, Station>) {
-    let string_slice = unsafe { str::from_utf8_unchecked(buffer) };
-    for line in string_slice.lines() {
-        let mut line_iter = line.split(';');
-        let name: &str = line_iter.next().expect("line should contain something");
-        let value: f64 = line_iter
-            .next()
-            .expect("line should contain a semicolon")
+fn process_buffer(buffer: &[u8], map: &mut BucketMap) {
+    for line in buffer.split(|&byte| byte == b'\n') {
+        if line.is_empty() {
+            continue;
+        }
+        let separator: usize = line
+            .iter()
+            .position(|&byte| byte == b';')
+            .expect("line should contain a semicolon");
+        let name: &[u8] = &line[..separator];
+        let value: f64 = unsafe { str::from_utf8_unchecked(&line[separator + 1..]) }
             .parse()
             .expect("second part should contain a valid number");
 
-        if map.contains_key(name) {
-            map.get_mut(name).unwrap().update(value);
-        } else {
-            map.insert(String::from(name), Station::from(value));
-        }
+        map.insert_or_update(name, value);
     }
 }
@@ -1,8 +1,12 @@
 #[test]
 fn test_station() {
-    use super::Station;
+    use crate::bucket_map::Station;
     let mut station = Station::from(0.5);
     station.update(-5.1);
     station.update(5.1);
-    assert_eq!(String::from("=-5.1/0.2/5.1"), station.drain());
+    let summary = station.summarize();
+    assert_eq!(summary.min, -5.1);
+    assert_eq!(summary.max, 5.1);
+    assert_eq!(format!("{:.1}", summary.mean), "0.2");
+    assert_eq!(summary.count, 3);
 }